@@ -1,12 +1,16 @@
 use clap::{crate_version, App, AppSettings, Arg, SubCommand};
 use failure::Fallible;
-use semver::VersionReq;
+use glob::glob;
+use semver::{Version, VersionReq};
 use serde_derive::Deserialize;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use toml::value::Table;
 
+mod resolve;
+mod vendor;
+
 const TEMP_PROJ_NAME: &str = "temp_prefetch_project";
 
 fn main() {
@@ -19,7 +23,7 @@ fn main() {
     }
 }
 
-type CrateSet = HashSet<(String, String)>;
+pub(crate) type CrateSet = HashSet<Package>;
 
 fn run() -> Fallible<()> {
     let app_matches = App::new("cargo-prefetch-dependencies")
@@ -42,6 +46,35 @@ fn run() -> Fallible<()> {
                         .takes_value(true)
                         .required(true),
                 )
+                .arg(
+                    Arg::with_name("locked")
+                        .long("locked")
+                        .help("Prefetch the exact versions pinned in Cargo.lock instead of re-resolving requirements"),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .help("Only include target-specific dependencies for this target triple")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("features")
+                        .long("features")
+                        .help("Space or comma separated list of features to activate")
+                        .takes_value(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("all-features")
+                        .long("all-features")
+                        .help("Activate all available features")
+                        .conflicts_with("no-default-features"),
+                )
+                .arg(
+                    Arg::with_name("no-default-features")
+                        .long("no-default-features")
+                        .help("Do not activate the `default` feature"),
+                )
                 .arg(
                     Arg::with_name("manifest")
                         .required(true)
@@ -56,30 +89,223 @@ fn run() -> Fallible<()> {
         .expect("Expected `prefetch-dependencies` subcommand.");
 
     let manifests = matches.values_of("manifest").unwrap();
+    let locked = matches.is_present("locked");
+    let target = matches.value_of("target");
+
+    let features = resolve::Features {
+        all_features: matches.is_present("all-features"),
+        no_default_features: matches.is_present("no-default-features"),
+        features: matches
+            .values_of("features")
+            .map(|values| {
+                values
+                    .flat_map(|value| value.split(|c: char| c == ',' || c.is_whitespace()))
+                    .filter(|feature| !feature.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
 
     // Default behavior with no command-line options.
     let mut crates: CrateSet = HashSet::new();
+    let mut skipped = Vec::new();
+    let mut locked_versions: HashMap<String, Vec<String>> = HashMap::new();
 
     for manifest_path in manifests {
-        let result: PackageDependencies = manifest_dependencies(manifest_path).unwrap();
+        collect_dependencies(
+            Path::new(manifest_path),
+            locked,
+            target,
+            &mut crates,
+            &mut locked_versions,
+            &mut skipped,
+        )?;
+    }
+
+    let crates = resolve::resolve(crates, &features, &locked_versions, &mut skipped)?;
+
+    if !skipped.is_empty() {
+        eprintln!(
+            "Skipped {} dependencies that could not be prefetched:",
+            skipped.len()
+        );
+        for dependency in &skipped {
+            eprintln!("  - {}: {}", dependency.name, dependency.reason);
+        }
+    }
+
+    let output = Path::new(matches.value_of("output").unwrap());
+    make_project(output, &crates)?;
+    vendor::fetch_and_vendor(output, &crates)
+}
+
+/// Read a manifest and fold its dependencies into `crates`. If the manifest
+/// declares a `[workspace]` table, its members are expanded and folded in
+/// too, so pointing the tool at a workspace root prefetches the whole repo.
+/// When `locked` is set and a `Cargo.lock` sits next to `manifest_path`, the
+/// exact versions it pins are used instead of the manifest's requirements.
+/// When `target` is set, only the `[target.*]` tables matching it (see
+/// `manifest_dependencies`) are included instead of the union of every
+/// platform; the top-level tables are always included. Entries that can't be
+/// prefetched (an unparsable version requirement, an unsupported source) are
+/// appended to `skipped` instead of silently disappearing. Every locked
+/// version this manifest's `Cargo.lock` pins is folded into `locked_versions`
+/// so `resolve` can honor `--locked` for transitive dependencies too, not
+/// just the direct ones pinned below.
+fn collect_dependencies(
+    manifest_path: &Path,
+    locked: bool,
+    target: Option<&str>,
+    crates: &mut CrateSet,
+    locked_versions: &mut HashMap<String, Vec<String>>,
+    skipped: &mut Vec<SkippedDependency>,
+) -> Fallible<()> {
+    let manifest_content = fs::read_to_string(manifest_path)?;
+    let data: Table = manifest_content.parse()?;
 
-        for dep in result.dependencies {
-            crates.insert((dep.name, dep.version));
+    let workspace_dependencies = data
+        .get("workspace")
+        .and_then(toml::Value::as_table)
+        .and_then(|workspace| workspace.get("dependencies"))
+        .and_then(toml::Value::as_table)
+        .cloned()
+        .unwrap_or_default();
+
+    if locked {
+        for (name, versions) in lockfile_versions(&manifest_path.with_file_name("Cargo.lock"))? {
+            locked_versions.entry(name).or_default().extend(versions);
         }
-        for dep in result.dev_dependencies {
-            crates.insert((dep.name, dep.version));
+    }
+
+    for member_manifest in workspace_manifests(manifest_path, &data)? {
+        let result =
+            manifest_dependencies(&member_manifest, &workspace_dependencies, target, skipped)?;
+
+        let deps = result
+            .dependencies
+            .into_iter()
+            .chain(result.dev_dependencies)
+            .chain(result.build_dependencies);
+
+        for mut dep in deps {
+            if let Source::Registry { version } = &mut dep.source {
+                let crate_name = dep.package.as_deref().unwrap_or(&dep.name);
+                let candidates = locked_versions
+                    .get(crate_name)
+                    .map(Vec::as_slice)
+                    .unwrap_or(&[]);
+                if let Some(locked_version) = select_locked_version(version, candidates) {
+                    *version = format!("={}", locked_version);
+                }
+            }
+            crates.insert(dep);
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse a `Cargo.lock` into a `name -> locked version(s)` map, mirroring
+/// how cargo-outdated consults the lock file of the project it's
+/// inspecting. Returns an empty map when no lock file exists next to the
+/// manifest. A name can map to more than one version: `Cargo.lock` routinely
+/// pins the same crate at two semver-incompatible majors for different
+/// dependents, so callers must pick the candidate matching their own
+/// requirement rather than taking an arbitrary one.
+fn lockfile_versions(lock_path: &Path) -> Fallible<HashMap<String, Vec<String>>> {
+    if !lock_path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let lock_content = fs::read_to_string(lock_path)?;
+    let lock: CargoLock = toml::from_str(&lock_content)?;
+
+    let mut versions: HashMap<String, Vec<String>> = HashMap::new();
+    for package in lock.package {
+        versions
+            .entry(package.name)
+            .or_default()
+            .push(package.version);
+    }
+
+    Ok(versions)
+}
+
+/// Pick the locked version satisfying `requirement` among `candidates`, the
+/// newest one if more than one matches. Returns `None` when `requirement`
+/// isn't a valid semver requirement or no candidate satisfies it.
+pub(crate) fn select_locked_version(requirement: &str, candidates: &[String]) -> Option<String> {
+    let requirement = VersionReq::parse(requirement).ok()?;
+
+    candidates
+        .iter()
+        .filter_map(|candidate| Version::parse(candidate).ok())
+        .filter(|version| requirement.matches(version))
+        .max()
+        .map(|version| version.to_string())
+}
+
+#[derive(Deserialize, Debug)]
+struct CargoLock {
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LockedPackage {
+    name: String,
+    version: String,
+}
+
+/// Expand a manifest into the manifest paths that should actually be parsed:
+/// itself, or — if it carries a `[workspace]` table — the manifests of its
+/// resolved `members`, minus anything matched by `exclude`.
+fn workspace_manifests(manifest_path: &Path, data: &Table) -> Fallible<Vec<PathBuf>> {
+    let workspace = match data.get("workspace").and_then(toml::Value::as_table) {
+        Some(workspace) => workspace,
+        None => return Ok(vec![manifest_path.to_path_buf()]),
+    };
+
+    let root = manifest_path.parent().unwrap_or_else(|| Path::new("."));
+    let mut members = member_manifests(root, workspace.get("members"))?;
+
+    for excluded in member_manifests(root, workspace.get("exclude"))? {
+        members.remove(&excluded);
+    }
+
+    // A workspace root can carry its own `[package]` table alongside
+    // `[workspace]`, in which case its own dependencies belong in the set too.
+    if data.contains_key("package") {
+        members.insert(manifest_path.to_path_buf());
+    }
+
+    Ok(members.into_iter().collect())
+}
+
+/// Expand the glob patterns of a `members`/`exclude` array into concrete
+/// member manifest paths.
+fn member_manifests(root: &Path, patterns: Option<&toml::Value>) -> Fallible<HashSet<PathBuf>> {
+    let mut manifests = HashSet::new();
+
+    let patterns = match patterns.and_then(toml::Value::as_array) {
+        Some(patterns) => patterns,
+        None => return Ok(manifests),
+    };
+
+    for pattern in patterns.iter().filter_map(toml::Value::as_str) {
+        let glob_pattern = root.join(pattern).join("Cargo.toml");
+
+        for entry in glob(&glob_pattern.to_string_lossy())? {
+            manifests.insert(entry?);
         }
     }
 
-    make_project(Path::new(matches.value_of("output").unwrap()), &crates)
+    Ok(manifests)
 }
 
 /// Create a temporary Cargo project with the given dependencies.
 fn make_project(path: &Path, crates: &CrateSet) -> Fallible<()> {
-    let deps: Vec<String> = crates
-        .iter()
-        .map(|(name, version)| format!("\"{}\" = \"{}\"\n", name, version))
-        .collect();
+    let deps: Vec<String> = crates.iter().map(dependency_line).collect();
 
     fs::write(
         path.join("Cargo.toml"),
@@ -101,73 +327,781 @@ fn make_project(path: &Path, crates: &CrateSet) -> Fallible<()> {
     Ok(())
 }
 
-#[derive(Deserialize, Debug, Clone)]
-struct Package {
-    name: String,
-    version: String,
+/// A dependency table entry: `name` is the key it's declared under (the
+/// local alias when renamed), `package` is the real crate name when it
+/// differs, and `source` is where the crate is actually fetched from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Package {
+    pub(crate) name: String,
+    pub(crate) package: Option<String>,
+    pub(crate) source: Source,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Source {
+    Registry {
+        version: String,
+    },
+    Git {
+        url: String,
+        reference: GitReference,
+    },
+    Path {
+        path: String,
+    },
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum GitReference {
+    Branch(String),
+    Tag(String),
+    Rev(String),
+    None,
+}
+
+impl GitReference {
+    /// The `, branch = "..."` style fragment to splice into a git dependency
+    /// table, or an empty string when no ref was pinned.
+    fn toml_fragment(&self) -> String {
+        match self {
+            GitReference::Branch(branch) => format!(", branch = \"{}\"", branch),
+            GitReference::Tag(tag) => format!(", tag = \"{}\"", tag),
+            GitReference::Rev(rev) => format!(", rev = \"{}\"", rev),
+            GitReference::None => String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 struct PackageDependencies {
     dev_dependencies: Vec<Package>,
     dependencies: Vec<Package>,
+    build_dependencies: Vec<Package>,
+}
+
+/// A dependency table entry this tool intentionally left out of the
+/// prefetch, and why, so users can see what wasn't fetched instead of it
+/// silently disappearing.
+#[derive(Debug, Clone)]
+pub(crate) struct SkippedDependency {
+    pub(crate) name: String,
+    pub(crate) reason: String,
 }
 
-fn transform_dependencies(deps: &toml::map::Map<String, toml::Value>) -> Vec<Package> {
+/// Resolve the dependency tables of a manifest, inlining `workspace = true`
+/// entries against the given `[workspace.dependencies]` table so inherited
+/// dependencies aren't dropped. Entries that can't be resolved are appended
+/// to `skipped` with the reason instead of being dropped silently.
+fn transform_dependencies(
+    deps: &toml::map::Map<String, toml::Value>,
+    workspace_dependencies: &toml::map::Map<String, toml::Value>,
+    manifest_dir: &Path,
+    skipped: &mut Vec<SkippedDependency>,
+) -> Vec<Package> {
     let mut result = Vec::new();
 
     for (key, val) in deps.iter() {
-        let version = if val.is_str() {
-            val.as_str()
+        let inherits_workspace = val
+            .as_table()
+            .and_then(|table| table.get("workspace"))
+            .and_then(toml::Value::as_bool)
+            .unwrap_or(false);
+
+        let val = if inherits_workspace {
+            match workspace_dependencies.get(key) {
+                Some(val) => val,
+                None => {
+                    skipped.push(SkippedDependency {
+                        name: key.to_string(),
+                        reason: "declares `workspace = true` but no matching \
+                                 [workspace.dependencies] entry was found"
+                            .to_string(),
+                    });
+                    continue;
+                }
+            }
         } else {
-            let value = val.as_table().unwrap();
-            value.get("version").unwrap().as_str()
-        }
-        .unwrap();
+            val
+        };
 
-        if VersionReq::parse(&version).is_ok() {
-            let dependency = Package {
-                name: key.to_string(),
-                version: version.to_string(),
-            };
+        let source = match dependency_source(val, manifest_dir) {
+            Ok(source) => source,
+            Err(reason) => {
+                skipped.push(SkippedDependency {
+                    name: key.to_string(),
+                    reason,
+                });
+                continue;
+            }
+        };
 
-            result.push(dependency);
-        }
+        let package = val
+            .as_table()
+            .and_then(|table| table.get("package"))
+            .and_then(toml::Value::as_str)
+            .map(str::to_string);
+
+        result.push(Package {
+            name: key.to_string(),
+            package,
+            source,
+        });
     }
 
-    return result;
+    result
 }
 
-/// Return the top downloaded crates by querying crates.io.
-fn manifest_dependencies(manifest: &str) -> Fallible<PackageDependencies> {
-    let path = std::path::Path::new(manifest);
-    let manifest_content = match std::fs::read_to_string(path) {
-        Ok(f) => f,
-        Err(e) => panic!("{}", e),
-    };
+/// Determine where a dependency table entry's crate comes from: a bare
+/// string or `version = "..."` is a registry dependency, `git = "..."`
+/// (optionally pinned with `branch`/`tag`/`rev`) is a git dependency, and
+/// `path = "..."` is a path dependency, rebased against `manifest_dir` (the
+/// directory of the manifest declaring it) since the generated project lives
+/// somewhere else entirely and a relative path must still resolve. Returns
+/// the reason as `Err` for entries this tool can't make sense of, such as a
+/// registry requirement that isn't valid semver.
+fn dependency_source(val: &toml::Value, manifest_dir: &Path) -> Result<Source, String> {
+    if let Some(version) = val.as_str() {
+        VersionReq::parse(version)
+            .map_err(|_| format!("invalid version requirement `{}`", version))?;
+        return Ok(Source::Registry {
+            version: version.to_string(),
+        });
+    }
 
-    let mut dependencies = Vec::new();
-    let mut dev_dependencies = Vec::new();
+    let table = val
+        .as_table()
+        .ok_or_else(|| "dependency entry is neither a string nor a table".to_string())?;
 
-    let data: Table = manifest_content.parse().unwrap();
-    let manifest_dependencies = data.get("dependencies");
+    if let Some(url) = table.get("git").and_then(toml::Value::as_str) {
+        let reference = if let Some(branch) = table.get("branch").and_then(toml::Value::as_str) {
+            GitReference::Branch(branch.to_string())
+        } else if let Some(tag) = table.get("tag").and_then(toml::Value::as_str) {
+            GitReference::Tag(tag.to_string())
+        } else if let Some(rev) = table.get("rev").and_then(toml::Value::as_str) {
+            GitReference::Rev(rev.to_string())
+        } else {
+            GitReference::None
+        };
 
-    if manifest_dependencies.is_some() {
-        let deps: &toml::map::Map<String, toml::Value> =
-            manifest_dependencies.unwrap().as_table().unwrap();
-        dependencies = transform_dependencies(deps);
+        return Ok(Source::Git {
+            url: url.to_string(),
+            reference,
+        });
     }
 
-    let manifest_dev_dependencies = data.get("dev-dependencies");
+    if let Some(path) = table.get("path").and_then(toml::Value::as_str) {
+        let path = Path::new(path);
+        let path = if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            manifest_dir.join(path)
+        };
 
-    if manifest_dev_dependencies.is_some() {
-        let dev_deps: &toml::map::Map<String, toml::Value> =
-            manifest_dev_dependencies.unwrap().as_table().unwrap();
-        dev_dependencies = transform_dependencies(dev_deps);
+        return Ok(Source::Path {
+            path: path.to_string_lossy().into_owned(),
+        });
+    }
+
+    let version = table
+        .get("version")
+        .and_then(toml::Value::as_str)
+        .ok_or_else(|| "missing `version`, `git`, or `path`".to_string())?;
+
+    VersionReq::parse(version).map_err(|_| format!("invalid version requirement `{}`", version))?;
+
+    Ok(Source::Registry {
+        version: version.to_string(),
+    })
+}
+
+/// Render a dependency as the `"name" = ...` line that goes into the
+/// generated `Cargo.toml`, choosing the table syntax cargo expects for git
+/// and path sources and threading through a `package` rename if present.
+fn dependency_line(dep: &Package) -> String {
+    let package = dep
+        .package
+        .as_ref()
+        .map(|package| format!(", package = \"{}\"", package))
+        .unwrap_or_default();
+
+    let body = match &dep.source {
+        Source::Registry { version } if package.is_empty() => format!("\"{}\"", version),
+        Source::Registry { version } => format!("{{ version = \"{}\"{} }}", version, package),
+        Source::Git { url, reference } => format!(
+            "{{ git = \"{}\"{}{} }}",
+            url,
+            reference.toml_fragment(),
+            package
+        ),
+        Source::Path { path } => format!("{{ path = \"{}\"{} }}", path, package),
+    };
+
+    format!("\"{}\" = {}\n", dep.name, body)
+}
+
+/// Read a manifest and return the dependencies declared in its tables.
+///
+/// Besides the manifest's own `dependencies`/`dev-dependencies`/
+/// `build-dependencies` tables, this walks `[target.'cfg(...)'.*]` and
+/// `[target.<triple>.*]` tables too. When `target` is given, only platform
+/// tables `target` actually matches are included — a literal triple, or a
+/// `cfg(...)` predicate evaluated against it — otherwise every platform's
+/// dependencies are unioned in. An unreadable or malformed manifest is
+/// surfaced as a `Fallible` error instead of panicking, so `run` can print it
+/// via its `Caused by:` chain.
+fn manifest_dependencies(
+    manifest: &Path,
+    workspace_dependencies: &toml::map::Map<String, toml::Value>,
+    target: Option<&str>,
+    skipped: &mut Vec<SkippedDependency>,
+) -> Fallible<PackageDependencies> {
+    let manifest_content = fs::read_to_string(manifest)?;
+    let data: Table = manifest_content.parse()?;
+    let manifest_dir = manifest.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut dependencies = table_dependencies(
+        &data,
+        "dependencies",
+        workspace_dependencies,
+        manifest_dir,
+        skipped,
+    );
+    let mut dev_dependencies = table_dependencies(
+        &data,
+        "dev-dependencies",
+        workspace_dependencies,
+        manifest_dir,
+        skipped,
+    );
+    let mut build_dependencies = table_dependencies(
+        &data,
+        "build-dependencies",
+        workspace_dependencies,
+        manifest_dir,
+        skipped,
+    );
+
+    if let Some(platforms) = data.get("target").and_then(toml::Value::as_table) {
+        for (key, platform) in platforms.iter() {
+            if let Some(target) = target {
+                if !target_matches(target, key) {
+                    continue;
+                }
+            }
+
+            let platform = match platform.as_table() {
+                Some(platform) => platform,
+                None => continue,
+            };
+
+            dependencies.extend(table_dependencies(
+                platform,
+                "dependencies",
+                workspace_dependencies,
+                manifest_dir,
+                skipped,
+            ));
+            dev_dependencies.extend(table_dependencies(
+                platform,
+                "dev-dependencies",
+                workspace_dependencies,
+                manifest_dir,
+                skipped,
+            ));
+            build_dependencies.extend(table_dependencies(
+                platform,
+                "build-dependencies",
+                workspace_dependencies,
+                manifest_dir,
+                skipped,
+            ));
+        }
     }
 
     Ok(PackageDependencies {
         dependencies,
         dev_dependencies,
+        build_dependencies,
     })
 }
+
+/// Whether a `[target.*]` table key applies to `target` (a triple like
+/// `x86_64-unknown-linux-gnu`): a literal key matches by equality, a
+/// `cfg(...)` key is evaluated against the `target_os`/`target_arch`/
+/// `target_env`/`target_vendor`/`target_family` facts derived from the
+/// triple.
+fn target_matches(target: &str, key: &str) -> bool {
+    match key
+        .strip_prefix("cfg(")
+        .and_then(|rest| rest.strip_suffix(')'))
+    {
+        Some(predicate) => eval_cfg(predicate, &TargetFacts::from_triple(target)),
+        None => key == target,
+    }
+}
+
+/// The handful of `cfg()` facts manifests actually gate dependencies on,
+/// derived from a target triple's `arch-vendor-os[-env]` components.
+struct TargetFacts {
+    os: String,
+    arch: String,
+    env: String,
+    vendor: String,
+    family: Option<&'static str>,
+}
+
+impl TargetFacts {
+    fn from_triple(target: &str) -> Self {
+        let parts: Vec<&str> = target.split('-').collect();
+        let arch = parts.first().copied().unwrap_or("unknown").to_string();
+        let vendor = parts.get(1).copied().unwrap_or("unknown").to_string();
+        let os_raw = parts.get(2).copied().unwrap_or("unknown");
+        let env = parts.get(3).copied().unwrap_or("").to_string();
+
+        let os = match os_raw {
+            "darwin" => "macos",
+            other => other,
+        }
+        .to_string();
+
+        let family = match os.as_str() {
+            "windows" => Some("windows"),
+            "linux" | "macos" | "android" | "ios" | "freebsd" | "openbsd" | "netbsd"
+            | "dragonfly" | "solaris" | "illumos" | "haiku" | "hurd" => Some("unix"),
+            _ => None,
+        };
+
+        TargetFacts {
+            os,
+            arch,
+            env,
+            vendor,
+            family,
+        }
+    }
+}
+
+/// Evaluate a `cfg(...)` predicate's inner expression (`unix`, `windows`,
+/// `key = "value"`, or `not`/`any`/`all` combinations of those) against
+/// `facts`.
+fn eval_cfg(predicate: &str, facts: &TargetFacts) -> bool {
+    let predicate = predicate.trim();
+
+    if let Some(inner) = predicate
+        .strip_prefix("not(")
+        .and_then(|rest| rest.strip_suffix(')'))
+    {
+        return !eval_cfg(inner, facts);
+    }
+
+    if let Some(inner) = predicate
+        .strip_prefix("any(")
+        .and_then(|rest| rest.strip_suffix(')'))
+    {
+        return split_cfg_args(inner).iter().any(|arg| eval_cfg(arg, facts));
+    }
+
+    if let Some(inner) = predicate
+        .strip_prefix("all(")
+        .and_then(|rest| rest.strip_suffix(')'))
+    {
+        return split_cfg_args(inner).iter().all(|arg| eval_cfg(arg, facts));
+    }
+
+    if let Some((key, value)) = predicate.split_once('=') {
+        let value = value.trim().trim_matches('"');
+        return match key.trim() {
+            "target_os" => facts.os == value,
+            "target_arch" => facts.arch == value,
+            "target_env" => facts.env == value,
+            "target_vendor" => facts.vendor == value,
+            "target_family" => facts.family == Some(value),
+            _ => false,
+        };
+    }
+
+    match predicate {
+        "unix" => facts.family == Some("unix"),
+        "windows" => facts.family == Some("windows"),
+        _ => false,
+    }
+}
+
+/// Split a `not`/`any`/`all` argument list on top-level commas, respecting
+/// nested parens so `any(unix, all(windows, target_env = "msvc"))` doesn't
+/// get split inside the nested `all(...)`.
+fn split_cfg_args(input: &str) -> Vec<&str> {
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, ch) in input.char_indices() {
+        match ch {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(input[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    let last = input[start..].trim();
+    if !last.is_empty() {
+        args.push(last);
+    }
+
+    args
+}
+
+/// Parse the dependency table at `key` within `table`, if present.
+fn table_dependencies(
+    table: &Table,
+    key: &str,
+    workspace_dependencies: &toml::map::Map<String, toml::Value>,
+    manifest_dir: &Path,
+    skipped: &mut Vec<SkippedDependency>,
+) -> Vec<Package> {
+    table
+        .get(key)
+        .and_then(toml::Value::as_table)
+        .map(|deps| transform_dependencies(deps, workspace_dependencies, manifest_dir, skipped))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_value(toml: &str) -> toml::Value {
+        let table: Table = toml.parse().unwrap();
+        table.into_iter().next().unwrap().1
+    }
+
+    #[test]
+    fn dependency_source_bare_version() {
+        let val = parse_value(r#"dep = "1.0""#);
+        let source = dependency_source(&val, Path::new(".")).unwrap();
+        assert_eq!(
+            source,
+            Source::Registry {
+                version: "1.0".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn dependency_source_invalid_version() {
+        let val = parse_value(r#"dep = "not-a-version""#);
+        assert!(dependency_source(&val, Path::new(".")).is_err());
+    }
+
+    #[test]
+    fn dependency_source_git_with_branch() {
+        let val = parse_value(r#"dep = { git = "https://example.com/dep", branch = "main" }"#);
+        let source = dependency_source(&val, Path::new(".")).unwrap();
+        assert_eq!(
+            source,
+            Source::Git {
+                url: "https://example.com/dep".to_string(),
+                reference: GitReference::Branch("main".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn dependency_source_git_with_tag() {
+        let val = parse_value(r#"dep = { git = "https://example.com/dep", tag = "v1.0" }"#);
+        let source = dependency_source(&val, Path::new(".")).unwrap();
+        assert_eq!(
+            source,
+            Source::Git {
+                url: "https://example.com/dep".to_string(),
+                reference: GitReference::Tag("v1.0".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn dependency_source_git_with_rev() {
+        let val = parse_value(r#"dep = { git = "https://example.com/dep", rev = "abc123" }"#);
+        let source = dependency_source(&val, Path::new(".")).unwrap();
+        assert_eq!(
+            source,
+            Source::Git {
+                url: "https://example.com/dep".to_string(),
+                reference: GitReference::Rev("abc123".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn dependency_source_git_without_reference() {
+        let val = parse_value(r#"dep = { git = "https://example.com/dep" }"#);
+        let source = dependency_source(&val, Path::new(".")).unwrap();
+        assert_eq!(
+            source,
+            Source::Git {
+                url: "https://example.com/dep".to_string(),
+                reference: GitReference::None,
+            }
+        );
+    }
+
+    #[test]
+    fn dependency_source_relative_path_is_rebased_against_manifest_dir() {
+        let val = parse_value(r#"dep = { path = "../dep" }"#);
+        let source = dependency_source(&val, Path::new("/workspace/crates/app")).unwrap();
+        assert_eq!(
+            source,
+            Source::Path {
+                path: "/workspace/crates/dep".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn dependency_source_absolute_path_is_untouched() {
+        let val = parse_value(r#"dep = { path = "/vendor/dep" }"#);
+        let source = dependency_source(&val, Path::new("/workspace/crates/app")).unwrap();
+        assert_eq!(
+            source,
+            Source::Path {
+                path: "/vendor/dep".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn dependency_source_missing_version_git_or_path() {
+        let val = parse_value(r#"dep = { optional = true }"#);
+        assert!(dependency_source(&val, Path::new(".")).is_err());
+    }
+
+    #[test]
+    fn dependency_source_non_table_non_string() {
+        let val = parse_value("dep = 1");
+        assert!(dependency_source(&val, Path::new(".")).is_err());
+    }
+
+    #[test]
+    fn dependency_line_registry_without_rename() {
+        let dep = Package {
+            name: "serde".to_string(),
+            package: None,
+            source: Source::Registry {
+                version: "1.0".to_string(),
+            },
+        };
+        assert_eq!(dependency_line(&dep), "\"serde\" = \"1.0\"\n");
+    }
+
+    #[test]
+    fn dependency_line_registry_with_rename() {
+        let dep = Package {
+            name: "serde1".to_string(),
+            package: Some("serde".to_string()),
+            source: Source::Registry {
+                version: "1.0".to_string(),
+            },
+        };
+        assert_eq!(
+            dependency_line(&dep),
+            "\"serde1\" = { version = \"1.0\", package = \"serde\" }\n"
+        );
+    }
+
+    #[test]
+    fn dependency_line_git_with_ref_and_rename() {
+        let dep = Package {
+            name: "serde1".to_string(),
+            package: Some("serde".to_string()),
+            source: Source::Git {
+                url: "https://example.com/serde".to_string(),
+                reference: GitReference::Branch("main".to_string()),
+            },
+        };
+        assert_eq!(
+            dependency_line(&dep),
+            "\"serde1\" = { git = \"https://example.com/serde\", branch = \"main\", package = \"serde\" }\n"
+        );
+    }
+
+    #[test]
+    fn dependency_line_path_with_rename() {
+        let dep = Package {
+            name: "serde1".to_string(),
+            package: Some("serde".to_string()),
+            source: Source::Path {
+                path: "/workspace/serde".to_string(),
+            },
+        };
+        assert_eq!(
+            dependency_line(&dep),
+            "\"serde1\" = { path = \"/workspace/serde\", package = \"serde\" }\n"
+        );
+    }
+
+    #[test]
+    fn select_locked_version_picks_newest_match() {
+        let candidates = vec![
+            "1.0.0".to_string(),
+            "1.2.0".to_string(),
+            "2.0.0".to_string(),
+        ];
+        assert_eq!(
+            select_locked_version("^1.0", &candidates),
+            Some("1.2.0".to_string())
+        );
+    }
+
+    #[test]
+    fn select_locked_version_none_when_nothing_matches() {
+        let candidates = vec!["2.0.0".to_string()];
+        assert_eq!(select_locked_version("^1.0", &candidates), None);
+    }
+
+    #[test]
+    fn select_locked_version_none_for_invalid_requirement() {
+        let candidates = vec!["1.0.0".to_string()];
+        assert_eq!(
+            select_locked_version("not-a-requirement", &candidates),
+            None
+        );
+    }
+
+    #[test]
+    fn workspace_manifests_without_workspace_table_is_itself() {
+        let data: Table = "".parse().unwrap();
+        let manifest_path = Path::new("/workspace/Cargo.toml");
+        let manifests = workspace_manifests(manifest_path, &data).unwrap();
+        assert_eq!(manifests, vec![manifest_path.to_path_buf()]);
+    }
+
+    #[test]
+    fn member_manifests_none_patterns_is_empty() {
+        let manifests = member_manifests(Path::new("."), None).unwrap();
+        assert!(manifests.is_empty());
+    }
+
+    #[test]
+    fn member_manifests_expands_globs() {
+        let dir = std::env::temp_dir().join(format!("cargo-prefetch-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::create_dir_all(dir.join("b")).unwrap();
+        fs::write(dir.join("a").join("Cargo.toml"), "").unwrap();
+        fs::write(dir.join("b").join("Cargo.toml"), "").unwrap();
+
+        let pattern = toml::Value::Array(vec![toml::Value::String("*".to_string())]);
+        let manifests = member_manifests(&dir, Some(&pattern)).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(manifests.len(), 2);
+        assert!(manifests.contains(&dir.join("a").join("Cargo.toml")));
+        assert!(manifests.contains(&dir.join("b").join("Cargo.toml")));
+    }
+
+    fn write_temp_manifest(name: &str, content: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let manifest_path = dir.join("Cargo.toml");
+        fs::write(&manifest_path, content).unwrap();
+        manifest_path
+    }
+
+    #[test]
+    fn manifest_dependencies_errors_instead_of_panicking_on_missing_manifest() {
+        let result = manifest_dependencies(
+            Path::new("/nonexistent/cargo-prefetch-test/Cargo.toml"),
+            &Table::new(),
+            None,
+            &mut Vec::new(),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn manifest_dependencies_errors_instead_of_panicking_on_malformed_toml() {
+        let manifest_path = write_temp_manifest("cargo-prefetch-malformed", "not valid [[[ toml");
+
+        let result = manifest_dependencies(&manifest_path, &Table::new(), None, &mut Vec::new());
+
+        fs::remove_dir_all(manifest_path.parent().unwrap()).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn manifest_dependencies_filters_by_literal_target_triple() {
+        let manifest_path = write_temp_manifest(
+            "cargo-prefetch-triple-target",
+            r#"
+[target.x86_64-unknown-linux-gnu.dependencies]
+libc = "0.2"
+
+[target.x86_64-pc-windows-msvc.dependencies]
+winapi = "0.3"
+"#,
+        );
+
+        let result = manifest_dependencies(
+            &manifest_path,
+            &Table::new(),
+            Some("x86_64-unknown-linux-gnu"),
+            &mut Vec::new(),
+        )
+        .unwrap();
+
+        fs::remove_dir_all(manifest_path.parent().unwrap()).unwrap();
+
+        assert_eq!(result.dependencies.len(), 1);
+        assert_eq!(result.dependencies[0].name, "libc");
+    }
+
+    #[test]
+    fn manifest_dependencies_filters_by_cfg_predicate() {
+        let manifest_path = write_temp_manifest(
+            "cargo-prefetch-cfg-target",
+            r#"
+[target.'cfg(unix)'.dependencies]
+libc = "0.2"
+
+[target.'cfg(windows)'.dependencies]
+winapi = "0.3"
+"#,
+        );
+
+        let result = manifest_dependencies(
+            &manifest_path,
+            &Table::new(),
+            Some("x86_64-unknown-linux-gnu"),
+            &mut Vec::new(),
+        )
+        .unwrap();
+
+        fs::remove_dir_all(manifest_path.parent().unwrap()).unwrap();
+
+        assert_eq!(result.dependencies.len(), 1);
+        assert_eq!(result.dependencies[0].name, "libc");
+    }
+
+    #[test]
+    fn transform_dependencies_skips_unresolvable_workspace_inheritance() {
+        let deps: Table = r#"foo = { workspace = true }"#.parse().unwrap();
+        let workspace_dependencies = Table::new();
+        let mut skipped = Vec::new();
+
+        let result =
+            transform_dependencies(&deps, &workspace_dependencies, Path::new("."), &mut skipped);
+
+        assert!(result.is_empty());
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].name, "foo");
+        assert_eq!(
+            skipped[0].reason,
+            "declares `workspace = true` but no matching [workspace.dependencies] entry was found"
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use crate::{select_locked_version, CrateSet, Package, SkippedDependency, Source};
+use cargo::core::dependency::{DepKind, Dependency};
+use cargo::core::registry::PackageRegistry;
+use cargo::core::summary::FeatureValue;
+use cargo::core::{QueryKind, Registry, SourceId, Summary};
+use cargo::util::Config;
+use failure::{err_msg, Fallible};
+use semver::{Version, VersionReq};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Which features to activate while walking the dependency graph, mirroring
+/// the flags `cargo build` itself accepts.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Features {
+    pub(crate) all_features: bool,
+    pub(crate) no_default_features: bool,
+    pub(crate) features: Vec<String>,
+}
+
+/// Resolve `crates` into its full transitive closure the way `cargo add`
+/// resolves a new dependency: build a `PackageRegistry` against crates.io,
+/// look up candidates for each registry requirement with
+/// `QueryKind::Exact`, take the newest match, and walk its activated
+/// dependencies until no new crates are discovered. Git and path
+/// dependencies aren't registry-resolvable, so they pass through unchanged.
+/// `locked_versions` is consulted before every registry lookup, direct or
+/// transitive, so `--locked` pins the whole graph the way `Cargo.lock`
+/// itself would rather than just the dependencies a manifest names outright.
+/// Two different requirements for the same crate name are common (a
+/// workspace can easily pin `log = "0.4"` in one member and `log = "0.3"` in
+/// another's `dev-dependencies`) — since only one concrete version ends up
+/// resolved per name, a requirement the resolved version doesn't satisfy is
+/// recorded in `skipped` instead of being dropped with no trace.
+pub(crate) fn resolve(
+    crates: CrateSet,
+    features: &Features,
+    locked_versions: &HashMap<String, Vec<String>>,
+    skipped: &mut Vec<SkippedDependency>,
+) -> Fallible<CrateSet> {
+    let config = Config::default()?;
+    let source_id = SourceId::crates_io(&config)?;
+    let mut registry = PackageRegistry::new(&config)?;
+
+    let mut resolved = CrateSet::new();
+    let mut seen: HashMap<String, String> = HashMap::new();
+    let mut queue: VecDeque<Package> = crates.into_iter().collect();
+
+    while let Some(dep) = queue.pop_front() {
+        let declared_requirement = match &dep.source {
+            Source::Registry { version } => version.clone(),
+            _ => {
+                resolved.insert(dep);
+                continue;
+            }
+        };
+
+        let crate_name = dep.package.clone().unwrap_or_else(|| dep.name.clone());
+
+        if let Some(resolved_version) = seen.get(&crate_name) {
+            if !requirement_is_satisfied(resolved_version, &declared_requirement) {
+                skipped.push(SkippedDependency {
+                    name: crate_name.clone(),
+                    reason: format!(
+                        "already resolved to {} to satisfy a different requirement; `{}` was \
+                         not also satisfied, so this dependency's own requirements may be \
+                         incomplete",
+                        resolved_version, declared_requirement
+                    ),
+                });
+            }
+            continue;
+        }
+
+        let requirement = locked_requirement(&crate_name, &declared_requirement, locked_versions);
+        let best = best_candidate(&mut registry, &crate_name, &requirement, source_id)?;
+
+        seen.insert(crate_name.clone(), best.version().to_string());
+
+        resolved.insert(Package {
+            name: dep.name.clone(),
+            package: dep.package.clone(),
+            source: Source::Registry {
+                version: format!("={}", best.version()),
+            },
+        });
+
+        let activated = activated_features(&best, features);
+
+        for transitive in activated_dependencies(best.dependencies(), features, &activated) {
+            queue.push_back(transitive);
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Whether the already-resolved `resolved_version` also satisfies
+/// `requirement`. An unparsable version or requirement is treated as
+/// satisfied so a malformed string doesn't produce a spurious skip report.
+fn requirement_is_satisfied(resolved_version: &str, requirement: &str) -> bool {
+    let version = match Version::parse(resolved_version) {
+        Ok(version) => version,
+        Err(_) => return true,
+    };
+
+    let requirement = match VersionReq::parse(requirement) {
+        Ok(requirement) => requirement,
+        Err(_) => return true,
+    };
+
+    requirement.matches(&version)
+}
+
+/// Pin `requirement` to the version `Cargo.lock` chose for `crate_name`, if
+/// one matches, so a dependency that's only reachable transitively still
+/// resolves to the locked version instead of whatever's newest on
+/// crates.io today.
+fn locked_requirement(
+    crate_name: &str,
+    requirement: &str,
+    locked_versions: &HashMap<String, Vec<String>>,
+) -> String {
+    let candidates = locked_versions
+        .get(crate_name)
+        .map(Vec::as_slice)
+        .unwrap_or(&[]);
+
+    match select_locked_version(requirement, candidates) {
+        Some(locked_version) => format!("={}", locked_version),
+        None => requirement.to_string(),
+    }
+}
+
+/// Query the registry for `crate_name` and return the newest package summary
+/// satisfying `requirement`.
+fn best_candidate(
+    registry: &mut PackageRegistry,
+    crate_name: &str,
+    requirement: &str,
+    source_id: SourceId,
+) -> Fallible<Summary> {
+    let query = Dependency::parse(crate_name, Some(requirement), source_id)?;
+
+    registry
+        .query_vec(&query, QueryKind::Exact)?
+        .into_iter()
+        .max_by_key(|summary| summary.version().clone())
+        .ok_or_else(|| err_msg(format!("No matching version found for `{}`", crate_name)))
+}
+
+/// Expand `summary`'s declared features into the full set of feature and
+/// dependency names they activate, starting from `default` (unless
+/// `no_default_features` is set) plus any `--features` named explicitly, and
+/// following `FeatureValue::Feature` references until the set stops growing.
+fn activated_features(summary: &Summary, features: &Features) -> HashSet<String> {
+    let feature_map = summary.features();
+    let mut activated = HashSet::new();
+    let mut queue: VecDeque<String> = VecDeque::new();
+
+    if !features.no_default_features {
+        queue.push_back("default".to_string());
+    }
+    queue.extend(features.features.iter().cloned());
+
+    while let Some(name) = queue.pop_front() {
+        if !activated.insert(name.clone()) {
+            continue;
+        }
+
+        let values = match feature_map.get(name.as_str()) {
+            Some(values) => values,
+            None => continue,
+        };
+
+        for value in values {
+            match value {
+                FeatureValue::Feature(feature) => queue.push_back(feature.to_string()),
+                FeatureValue::Dep { dep_name } => {
+                    activated.insert(dep_name.to_string());
+                }
+                // `dep_name/feature` also turns `dep_name` on; `dep_name?/feature`
+                // (weak) only forwards the feature if something else already
+                // turned it on, so it must not activate `dep_name` by itself.
+                FeatureValue::DepFeature {
+                    dep_name,
+                    weak: false,
+                    ..
+                } => {
+                    activated.insert(dep_name.to_string());
+                }
+                FeatureValue::DepFeature { weak: true, .. } => {}
+            }
+        }
+    }
+
+    activated
+}
+
+/// Expand a resolved summary's dependency list into the `Package`s that
+/// `features` actually activates: non-dev dependencies that aren't optional,
+/// plus optional ones named by `activated` (or all of them under
+/// `--all-features`). Each `Package` keeps the dependency's real registry
+/// name in `package` whenever it differs from the local alias it's declared
+/// under, the same rename cargo itself tracks.
+fn activated_dependencies(
+    deps: &[Dependency],
+    features: &Features,
+    activated: &HashSet<String>,
+) -> Vec<Package> {
+    deps.iter()
+        .filter(|dep| dep.kind() != DepKind::Development)
+        .filter(|dep| {
+            !dep.is_optional()
+                || features.all_features
+                || activated.contains(dep.name_in_toml().as_str())
+        })
+        .map(|dep| {
+            let name = dep.name_in_toml().to_string();
+            let package_name = dep.package_name().to_string();
+            let package = if package_name != name {
+                Some(package_name)
+            } else {
+                None
+            };
+
+            Package {
+                name,
+                package,
+                source: Source::Registry {
+                    version: dep.version_req().to_string(),
+                },
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,203 @@
+use crate::{CrateSet, Source};
+use failure::{bail, err_msg, Fallible};
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tar::Archive;
+
+/// Run `cargo fetch` against the temp project written by `make_project`,
+/// then unpack every registry crate `crates` resolved to into a `vendor/`
+/// directory under `project_path` and point a `.cargo/config.toml` at it, so
+/// the result can be copied into an air-gapped cache and built with `cargo
+/// build --offline`. Git and path dependencies are not unpacked into
+/// `vendor/`.
+pub fn fetch_and_vendor(project_path: &Path, crates: &CrateSet) -> Fallible<()> {
+    run_cargo_fetch(project_path)?;
+
+    let vendor_dir = project_path.join("vendor");
+    fs::create_dir_all(&vendor_dir)?;
+
+    for cache_file in registry_cache_files(&expected_cache_files(crates))? {
+        unpack_crate(&cache_file, &vendor_dir)?;
+    }
+
+    write_vendor_config(project_path)
+}
+
+fn run_cargo_fetch(project_path: &Path) -> Fallible<()> {
+    let status = Command::new("cargo")
+        .arg("fetch")
+        .arg("--manifest-path")
+        .arg(project_path.join("Cargo.toml"))
+        .status()?;
+
+    if !status.success() {
+        bail!("`cargo fetch` exited with {}", status);
+    }
+
+    Ok(())
+}
+
+/// The `name-version.crate` file names this run's registry dependencies
+/// resolved to, so the vendored directory only ever contains crates this
+/// tool actually asked for rather than whatever else `cargo fetch` happens
+/// to have accumulated in the shared cache over time.
+fn expected_cache_files(crates: &CrateSet) -> Vec<String> {
+    crates
+        .iter()
+        .filter_map(|dep| match &dep.source {
+            Source::Registry { version } => {
+                let name = dep.package.clone().unwrap_or_else(|| dep.name.clone());
+                Some(format!(
+                    "{}-{}.crate",
+                    name,
+                    version.trim_start_matches('=')
+                ))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Look up each of `file_names` across every registry host directory cargo
+/// has cached crates under, failing loudly if `cargo fetch` didn't leave one
+/// of them behind.
+fn registry_cache_files(file_names: &[String]) -> Fallible<Vec<PathBuf>> {
+    let cache_root = cargo_home()?.join("registry").join("cache");
+    let mut files = Vec::new();
+
+    for file_name in file_names {
+        match find_cache_file(&cache_root, file_name)? {
+            Some(path) => files.push(path),
+            None => bail!(
+                "expected cached crate file `{}` was not found after `cargo fetch`",
+                file_name
+            ),
+        }
+    }
+
+    Ok(files)
+}
+
+fn find_cache_file(cache_root: &Path, file_name: &str) -> Fallible<Option<PathBuf>> {
+    if !cache_root.exists() {
+        return Ok(None);
+    }
+
+    for host_dir in fs::read_dir(cache_root)? {
+        let candidate = host_dir?.path().join(file_name);
+
+        if candidate.exists() {
+            return Ok(Some(candidate));
+        }
+    }
+
+    Ok(None)
+}
+
+fn cargo_home() -> Fallible<PathBuf> {
+    if let Ok(cargo_home) = std::env::var("CARGO_HOME") {
+        return Ok(PathBuf::from(cargo_home));
+    }
+
+    let home = dirs::home_dir().ok_or_else(|| err_msg("Could not determine home directory"))?;
+    Ok(home.join(".cargo"))
+}
+
+/// Decompress and unpack a single `.crate` gzip/tar into `vendor_dir`, then
+/// write the `.cargo-checksum.json` cargo's directory-source loader requires
+/// next to it, since an unpacked tarball with no checksum file is silently
+/// rejected by `cargo build --offline` against `[source.vendored-sources]`.
+fn unpack_crate(crate_path: &Path, vendor_dir: &Path) -> Fallible<()> {
+    let file = fs::File::open(crate_path)?;
+    let mut archive = Archive::new(GzDecoder::new(file));
+    archive.unpack(vendor_dir)?;
+
+    let package_dir_name = crate_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| err_msg(format!("`{}` has no file stem", crate_path.display())))?;
+
+    write_checksum_file(&vendor_dir.join(package_dir_name), crate_path)
+}
+
+/// Write the `.files`/`.package` sha256 manifest cargo's vendored-source
+/// loader checks before trusting an unpacked crate directory.
+fn write_checksum_file(package_dir: &Path, crate_path: &Path) -> Fallible<()> {
+    let package_hash = sha256_file(crate_path)?;
+
+    let mut files = BTreeMap::new();
+    for path in walk_files(package_dir)? {
+        let relative = path
+            .strip_prefix(package_dir)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .replace('\\', "/");
+        files.insert(relative, sha256_file(&path)?);
+    }
+
+    let files_json = files
+        .iter()
+        .map(|(path, hash)| format!("{}:{}", json_string(path), json_string(hash)))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    fs::write(
+        package_dir.join(".cargo-checksum.json"),
+        format!(
+            "{{\"files\":{{{}}},\"package\":{}}}",
+            files_json,
+            json_string(&package_hash)
+        ),
+    )?;
+
+    Ok(())
+}
+
+fn walk_files(dir: &Path) -> Fallible<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            files.extend(walk_files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+fn sha256_file(path: &Path) -> Fallible<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(&fs::read(path)?);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn json_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Point a `.cargo/config.toml` at the vendored directory so `cargo build
+/// --offline` resolves against it instead of the network.
+fn write_vendor_config(project_path: &Path) -> Fallible<()> {
+    let config_dir = project_path.join(".cargo");
+    fs::create_dir_all(&config_dir)?;
+
+    fs::write(
+        config_dir.join("config.toml"),
+        r#"[source.crates-io]
+replace-with = "vendored-sources"
+
+[source.vendored-sources]
+directory = "vendor"
+"#,
+    )?;
+
+    Ok(())
+}